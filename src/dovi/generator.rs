@@ -13,7 +13,9 @@ use super::rpu::{
     rpu_data_header::RpuDataHeader, vdr_dm_data::VdrDmData, vdr_rpu_data::VdrRpuData,
 };
 
-use super::CmXmlParser;
+use super::{CmXmlParser, Shot};
+
+use super::mp4_muxer::{self, DoviDecoderConfigurationRecord};
 
 pub struct Generator {
     json_path: Option<PathBuf>,
@@ -22,7 +24,7 @@ pub struct Generator {
     xml_path: Option<PathBuf>,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct GenerateConfig {
     pub length: u64,
     pub target_nits: Option<u16>,
@@ -33,12 +35,167 @@ pub struct GenerateConfig {
     #[serde(default)]
     pub source_max_pq: Option<u16>,
 
+    #[serde(default)]
+    pub cm_version: CmVersion,
+
     pub level2: Option<Vec<Level2Metadata>>,
     pub level5: Option<Level5Metadata>,
     pub level6: Option<Level6Metadata>,
+
+    /// Per-shot, per-frame L1/L2/L3/L5 metadata, shaped like the shots `CmXmlParser` produces.
+    /// When present, takes over from the uniform `length`/`target_nits` generation below.
+    #[serde(default)]
+    pub shots: Option<Vec<Shot>>,
+
+    #[serde(default)]
+    pub level8: Option<Vec<Level8Metadata>>,
+    #[serde(default)]
+    pub level9: Option<Level9Metadata>,
+    #[serde(default)]
+    pub level11: Option<Level11Metadata>,
+    #[serde(default)]
+    pub level254: Option<Level254Metadata>,
+
+    /// The mastering display, defined by its color primaries and luminance instead of
+    /// precomputed PQ codes. Fills `source_min_pq`/`source_max_pq`, `level6` and `level9` when
+    /// those aren't set explicitly.
+    #[serde(default)]
+    pub source_display: Option<DisplayCharacteristics>,
+
+    /// Named target displays that `Level2Metadata`/`Level8Metadata` entries can reference via
+    /// `target_display` instead of a bare `target_nits`/`target_display_index`.
+    #[serde(default)]
+    pub targets: Option<Vec<DisplayCharacteristics>>,
+
+    /// When set, also package the generated RPUs in a fragmented MP4 alongside the raw NAL
+    /// dump, written next to `rpu_out` with a `.mp4` extension. Only the uniform/HDR10+
+    /// generation writes it; `shots` input produces the raw NAL dump only.
+    #[serde(default)]
+    pub mp4: bool,
+}
+
+/// A display defined by its RGB color primaries and white point chromaticity (CIE 1931 xy) and
+/// its min/max mastering luminance in nits, mirroring the display-characteristics model used by
+/// the `dovi_meta` crate.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct DisplayCharacteristics {
+    pub name: Option<String>,
+
+    pub red_x: f64,
+    pub red_y: f64,
+    pub green_x: f64,
+    pub green_y: f64,
+    pub blue_x: f64,
+    pub blue_y: f64,
+    pub white_x: f64,
+    pub white_y: f64,
+
+    pub min_luminance: f64,
+    pub max_luminance: f64,
+}
+
+/// The three sets of primaries DoVi L9 can select as the source color gamut, as
+/// `(red, green, blue, white)` CIE 1931 xy chromaticity coordinates.
+const STANDARD_PRIMARIES: [([f64; 2], [f64; 2], [f64; 2], [f64; 2]); 3] = [
+    // Rec.709
+    (
+        [0.640, 0.330],
+        [0.300, 0.600],
+        [0.150, 0.060],
+        [0.3127, 0.3290],
+    ),
+    // DCI-P3 D65
+    (
+        [0.680, 0.320],
+        [0.265, 0.690],
+        [0.150, 0.060],
+        [0.3127, 0.3290],
+    ),
+    // Rec.2020
+    (
+        [0.708, 0.292],
+        [0.170, 0.797],
+        [0.131, 0.046],
+        [0.3127, 0.3290],
+    ),
+];
+
+impl DisplayCharacteristics {
+    /// Converts `min_luminance`/`max_luminance` (in nits) to the PQ codes `source_min_pq`/
+    /// `source_max_pq` expect, at full floating-point precision. `nits_to_pq` only takes whole
+    /// nits, which would collapse a real mastering display's sub-nit black level (typically
+    /// ~0.005 nits) to a PQ code of 0, so the black level is run through the PQ OETF directly
+    /// instead.
+    fn source_pq(&self) -> (u16, u16) {
+        let min_pq = (nits_to_pq_precise(self.min_luminance) * 4095.0).round() as u16;
+        let max_pq = (nits_to_pq_precise(self.max_luminance) * 4095.0).round() as u16;
+
+        (min_pq, max_pq)
+    }
+
+    /// Derives the L6 MDL/MaxCLL fields from this display's mastering luminance. MaxFALL isn't
+    /// something a display's static characteristics can tell us (it depends on the actual
+    /// content), so it's estimated as half of MaxCLL rather than claiming the peak luminance is
+    /// also the average of the brightest frame.
+    fn level6_metadata(&self) -> Level6Metadata {
+        Level6Metadata {
+            max_display_mastering_luminance: self.max_luminance.round() as u16,
+            min_display_mastering_luminance: (self.min_luminance * 10000.0).round() as u16,
+            max_content_light_level: self.max_luminance.round() as u16,
+            max_frame_average_light_level: (self.max_luminance / 2.0).round() as u16,
+        }
+    }
+
+    /// Maps this display's chromaticity to the nearest standard primaries, for the L9
+    /// `source_primary_index` (0 = Rec.709, 1 = P3-D65, 2 = Rec.2020).
+    fn nearest_standard_primary_index(&self) -> u8 {
+        STANDARD_PRIMARIES
+            .iter()
+            .map(|(red, green, blue, white)| {
+                (self.red_x - red[0]).powi(2)
+                    + (self.red_y - red[1]).powi(2)
+                    + (self.green_x - green[0]).powi(2)
+                    + (self.green_y - green[1]).powi(2)
+                    + (self.blue_x - blue[0]).powi(2)
+                    + (self.blue_y - blue[1]).powi(2)
+                    + (self.white_x - white[0]).powi(2)
+                    + (self.white_y - white[1]).powi(2)
+            })
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .map(|(i, _)| i as u8)
+            .unwrap_or(0)
+    }
 }
 
-#[derive(Default, Debug, Clone)]
+/// Finds the named target display and its index among `targets`.
+fn find_target_display<'a>(
+    target_display: &Option<String>,
+    targets: &'a [DisplayCharacteristics],
+) -> Option<(usize, &'a DisplayCharacteristics)> {
+    let name = target_display.as_ref()?;
+
+    targets
+        .iter()
+        .enumerate()
+        .find(|(_, t)| t.name.as_deref() == Some(name.as_str()))
+}
+
+/// Selects which flavour of CM (Content Mapping) DM extension blocks are generated.
+/// `CmV40` unlocks the L8/L9/L11/L254 blocks, flagged through L254's `dm_version_index`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmVersion {
+    CmV29,
+    CmV40,
+}
+
+impl Default for CmVersion {
+    fn default() -> Self {
+        CmVersion::CmV29
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct Level1Metadata {
     pub min_pq: u16,
     pub max_pq: u16,
@@ -47,8 +204,14 @@ pub struct Level1Metadata {
 
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct Level2Metadata {
+    #[serde(default)]
     pub target_nits: u16,
 
+    /// Looked up in `GenerateConfig::targets` to fill `target_nits`, so the trim can be
+    /// authored against a named real panel instead of a bare nits value.
+    #[serde(default)]
+    pub target_display: Option<String>,
+
     #[serde(default = "default_trim")]
     pub trim_slope: u16,
     #[serde(default = "default_trim")]
@@ -63,14 +226,14 @@ pub struct Level2Metadata {
     pub ms_weight: i16,
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct Level3Metadata {
     pub min_pq_offset: u16,
     pub max_pq_offset: u16,
     pub avg_pq_offset: u16,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct Level5Metadata {
     pub active_area_left_offset: u16,
     pub active_area_right_offset: u16,
@@ -86,6 +249,118 @@ pub struct Level6Metadata {
     pub max_frame_average_light_level: u16,
 }
 
+/// CM v4.0 trim, tied to a target display via `target_display_index` instead of a bare nits value.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct Level8Metadata {
+    #[serde(default)]
+    pub target_display_index: u8,
+
+    /// Looked up in `GenerateConfig::targets` to fill `target_display_index` by the target's
+    /// position in that list, so the trim can be authored against a named real panel.
+    #[serde(default)]
+    pub target_display: Option<String>,
+
+    #[serde(default = "default_trim")]
+    pub trim_slope: u16,
+    #[serde(default = "default_trim")]
+    pub trim_offset: u16,
+    #[serde(default = "default_trim")]
+    pub trim_power: u16,
+    #[serde(default = "default_trim")]
+    pub trim_chroma_weight: u16,
+    #[serde(default = "default_trim")]
+    pub trim_saturation_gain: u16,
+    #[serde(default = "default_trim_neg")]
+    pub ms_weight: i16,
+
+    #[serde(default = "default_trim")]
+    pub target_mid_contrast: u16,
+    #[serde(default = "default_trim")]
+    pub clip_trim: u16,
+}
+
+/// CM v4.0: selects the mastering display color primaries used as the source gamut.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct Level9Metadata {
+    pub source_primary_index: u8,
+}
+
+/// CM v4.0: content type and reference viewing environment metadata.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Level11Metadata {
+    pub content_type: u8,
+    pub whitepoint: u8,
+    pub reference_mode_flag: bool,
+}
+
+impl Default for Level11Metadata {
+    fn default() -> Self {
+        Self {
+            content_type: 1,
+            whitepoint: 0,
+            reference_mode_flag: true,
+        }
+    }
+}
+
+/// CM v4.0: `dm_version_index` is what actually flags a stream as CM v4.0 to decoders.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Level254Metadata {
+    pub dm_mode: u8,
+    pub dm_version_index: u8,
+}
+
+impl Default for Level254Metadata {
+    fn default() -> Self {
+        Self {
+            dm_mode: 0,
+            dm_version_index: 2,
+        }
+    }
+}
+
+impl GenerateConfig {
+    /// Resolves `source_display`/`targets` into the PQ codes, L6 and L9 fields the rest of the
+    /// generator expects, and any `target_display` trim references into concrete values. Called
+    /// once before a config is used to build RPUs.
+    fn resolve_displays(&self) -> GenerateConfig {
+        let mut config = self.clone();
+
+        if let Some(source_display) = &self.source_display {
+            let (min_pq, max_pq) = source_display.source_pq();
+
+            config.source_min_pq.get_or_insert(min_pq);
+            config.source_max_pq.get_or_insert(max_pq);
+            config
+                .level6
+                .get_or_insert_with(|| source_display.level6_metadata());
+            config.level9.get_or_insert_with(|| Level9Metadata {
+                source_primary_index: source_display.nearest_standard_primary_index(),
+            });
+        }
+
+        if let Some(targets) = &self.targets {
+            if let Some(level2) = &mut config.level2 {
+                for l2 in level2 {
+                    if let Some((_, display)) = find_target_display(&l2.target_display, targets) {
+                        l2.target_nits = display.max_luminance.round() as u16;
+                    }
+                }
+            }
+
+            if let Some(level8) = &mut config.level8 {
+                for l8 in level8 {
+                    if let Some((index, _)) = find_target_display(&l8.target_display, targets) {
+                        l8.target_display_index = index as u8;
+                    }
+                }
+            }
+        }
+
+        config
+    }
+}
+
 impl Generator {
     pub fn generate(
         json_path: Option<PathBuf>,
@@ -127,7 +402,25 @@ impl Generator {
     }
 
     fn execute(&self, config: &GenerateConfig) -> Result<(), std::io::Error> {
-        let (l1_meta, scene_cuts) = parse_hdr10plus_for_l1(&self.hdr10plus_path);
+        let config = &config.resolve_displays();
+
+        if let Some(shots) = &config.shots {
+            let mut writer = BufWriter::with_capacity(
+                100_000,
+                File::create(&self.rpu_out).expect("Can't create file"),
+            );
+
+            let length = write_shots_rpu(&mut writer, shots, config)?;
+
+            println!("Generated metadata for {} frames", length);
+
+            writer.flush()?;
+
+            return Ok(());
+        }
+
+        let (l1_meta, l2_meta, scene_cuts) =
+            parse_hdr10plus_for_l1(&self.hdr10plus_path, config.target_nits);
 
         let mut writer = BufWriter::with_capacity(
             100_000,
@@ -140,6 +433,12 @@ impl Generator {
             config.length as usize
         };
 
+        let mut mp4_rpus = if config.mp4 {
+            Some(Vec::with_capacity(length))
+        } else {
+            None
+        };
+
         for i in 0..length {
             let mut rpu = DoviRpu {
                 dovi_profile: 8,
@@ -152,7 +451,7 @@ impl Generator {
                 ..Default::default()
             };
 
-            let encoded_rpu = if let Some(l1_list) = &l1_meta {
+            if let Some(l1_list) = &l1_meta {
                 if let Some(meta) = &l1_list.get(i) {
                     if let Some(dm_meta) = &mut rpu.vdr_dm_data {
                         dm_meta.add_level1_metadata(meta.min_pq, meta.max_pq, meta.avg_pq);
@@ -160,24 +459,73 @@ impl Generator {
                         if scene_cuts.contains(&i) {
                             dm_meta.set_scene_cut(true);
                         }
+
+                        if let Some(l2_list) = &l2_meta {
+                            if let Some(Some(l2)) = l2_list.get(i) {
+                                dm_meta.add_level2_metadata(
+                                    l2.target_nits,
+                                    l2.trim_slope,
+                                    l2.trim_offset,
+                                    l2.trim_power,
+                                    l2.trim_chroma_weight,
+                                    l2.trim_saturation_gain,
+                                    l2.ms_weight,
+                                );
+                            }
+                        }
                     }
                 }
+            }
 
-                rpu.write_rpu_data()
-            } else {
-                rpu.write_rpu_data()
-            };
+            // Applies regardless of whether this frame's L1/L2 came from HDR10+ input, so a
+            // plain authored `GenerateConfig` (the normal CM v4.0 path) isn't dropped.
+            if config.cm_version == CmVersion::CmV40 {
+                if let Some(dm_meta) = &mut rpu.vdr_dm_data {
+                    add_cm_v40_metadata(dm_meta, config);
+                }
+            }
+
+            let encoded_rpu = rpu.write_rpu_data();
 
             writer.write_all(OUT_NAL_HEADER)?;
 
             // Remove 0x7C01
             writer.write_all(&encoded_rpu[2..])?;
+
+            if let Some(mp4_rpus) = &mut mp4_rpus {
+                mp4_rpus.push(encoded_rpu[2..].to_vec());
+            }
         }
 
         println!("Generated metadata for {} frames", length);
 
         writer.flush()?;
 
+        if let Some(mp4_rpus) = mp4_rpus {
+            self.write_mp4(&mp4_rpus)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the generated RPUs into a fragmented MP4 carrying the Dolby Vision decoder
+    /// configuration record, next to `rpu_out` with a `.mp4` extension.
+    fn write_mp4(&self, rpus: &[Vec<u8>]) -> Result<(), std::io::Error> {
+        let rpu = DoviRpu {
+            dovi_profile: 8,
+            ..Default::default()
+        };
+
+        // The real level depends on the output resolution/framerate, which the generator
+        // doesn't track; 6 covers up to 4K30, the common case for generated DoVi streams.
+        let config_record = DoviDecoderConfigurationRecord::from_rpu(&rpu, 6);
+
+        let mp4_out = self.rpu_out.with_extension("mp4");
+
+        mp4_muxer::write_fmp4(rpus, &config_record, &mp4_out)?;
+
+        println!("Generated {}", mp4_out.display());
+
         Ok(())
     }
 
@@ -190,96 +538,278 @@ impl Generator {
 
         let parser = CmXmlParser::new(s);
 
-        let length = parser.get_video_length();
         let level6 = parser.get_hdr10_metadata();
+        let shots = parser.get_shots();
+
+        let mut writer = BufWriter::with_capacity(
+            100_000,
+            File::create(&self.rpu_out).expect("Can't create file"),
+        );
 
         let config = GenerateConfig {
             length: 0,
-            level6: Some(level6.clone()),
+            level6: Some(level6),
             ..Default::default()
         };
 
-        let mut writer = BufWriter::with_capacity(
-            100_000,
-            File::create(&self.rpu_out).expect("Can't create file"),
-        );
+        let length = write_shots_rpu(&mut writer, &shots, &config)?;
 
-        let shots = parser.get_shots();
+        println!("Generated metadata for {} frames", length);
 
-        for shot in shots {
-            let end = shot.duration;
-
-            for i in 0..end {
-                let mut rpu = DoviRpu {
-                    dovi_profile: 8,
-                    modified: true,
-                    header: RpuDataHeader::p8_default(),
-                    vdr_rpu_data: Some(VdrRpuData::p8_default()),
-                    nlq_data: None,
-                    vdr_dm_data: Some(VdrDmData::from_config(&config)),
-                    last_byte: 0x80,
-                    ..Default::default()
-                };
+        writer.flush()?;
 
-                if let Some(dm_meta) = &mut rpu.vdr_dm_data {
-                    if let Some(l1_list) = &shot.level1 {
-                        if let Some(meta) = l1_list.get(i) {
-                            dm_meta.add_level1_metadata(meta.min_pq, meta.max_pq, meta.avg_pq);
+        Ok(())
+    }
+}
 
-                            if i == 0 {
-                                dm_meta.set_scene_cut(true);
-                            }
+/// Walks `shots` in order, emitting one RPU per frame from each shot's per-frame L1/L2/L3
+/// metadata and its L5 active area, if set. Shared by the XML and JSON shot-metadata inputs
+/// so both converge on the same per-frame RPU assembly.
+fn write_shots_rpu(
+    writer: &mut BufWriter<File>,
+    shots: &[Shot],
+    parent_config: &GenerateConfig,
+) -> Result<usize, std::io::Error> {
+    let mut frame_count = 0;
+
+    for shot in shots {
+        let config = GenerateConfig {
+            length: 0,
+            source_min_pq: parent_config.source_min_pq,
+            source_max_pq: parent_config.source_max_pq,
+            level2: parent_config.level2.clone(),
+            level5: shot.level5.clone(),
+            level6: parent_config.level6.clone(),
+            cm_version: parent_config.cm_version,
+            level8: parent_config.level8.clone(),
+            level9: parent_config.level9.clone(),
+            level11: parent_config.level11.clone(),
+            level254: parent_config.level254.clone(),
+            ..Default::default()
+        };
+
+        for i in 0..shot.duration {
+            let mut rpu = DoviRpu {
+                dovi_profile: 8,
+                modified: true,
+                header: RpuDataHeader::p8_default(),
+                vdr_rpu_data: Some(VdrRpuData::p8_default()),
+                nlq_data: None,
+                vdr_dm_data: Some(VdrDmData::from_config(&config)),
+                last_byte: 0x80,
+                ..Default::default()
+            };
+
+            if let Some(dm_meta) = &mut rpu.vdr_dm_data {
+                if let Some(l1_list) = &shot.level1 {
+                    if let Some(meta) = l1_list.get(i) {
+                        dm_meta.add_level1_metadata(meta.min_pq, meta.max_pq, meta.avg_pq);
+
+                        if i == 0 {
+                            dm_meta.set_scene_cut(true);
                         }
                     }
+                }
 
-                    if let Some(l2_list) = &shot.level2 {
-                        if let Some(meta) = l2_list.get(i) {
-                            for l2 in meta {
-                                dm_meta.add_level2_metadata(
-                                    l2.target_nits,
-                                    l2.trim_slope,
-                                    l2.trim_offset,
-                                    l2.trim_power,
-                                    l2.trim_chroma_weight,
-                                    l2.trim_saturation_gain,
-                                    l2.ms_weight,
-                                )
-                            }
+                if let Some(l2_list) = &shot.level2 {
+                    if let Some(meta) = l2_list.get(i) {
+                        for l2 in meta {
+                            dm_meta.add_level2_metadata(
+                                l2.target_nits,
+                                l2.trim_slope,
+                                l2.trim_offset,
+                                l2.trim_power,
+                                l2.trim_chroma_weight,
+                                l2.trim_saturation_gain,
+                                l2.ms_weight,
+                            )
                         }
                     }
+                }
 
-                    if let Some(l3_list) = &shot.level3 {
-                        if let Some(meta) = l3_list.get(i) {
-                            dm_meta.add_level3_metadata(
-                                meta.min_pq_offset,
-                                meta.max_pq_offset,
-                                meta.avg_pq_offset,
-                            );
-                        }
+                if let Some(l3_list) = &shot.level3 {
+                    if let Some(meta) = l3_list.get(i) {
+                        dm_meta.add_level3_metadata(
+                            meta.min_pq_offset,
+                            meta.max_pq_offset,
+                            meta.avg_pq_offset,
+                        );
                     }
                 }
 
-                let encoded_rpu = rpu.write_rpu_data();
+                if config.cm_version == CmVersion::CmV40 {
+                    add_cm_v40_metadata(dm_meta, &config);
+                }
+            }
 
-                writer.write_all(OUT_NAL_HEADER)?;
+            let encoded_rpu = rpu.write_rpu_data();
 
-                // Remove 0x7C01
-                writer.write_all(&encoded_rpu[2..])?;
-            }
+            writer.write_all(OUT_NAL_HEADER)?;
+
+            // Remove 0x7C01
+            writer.write_all(&encoded_rpu[2..])?;
+
+            frame_count += 1;
         }
+    }
 
-        println!("Generated metadata for {} frames", length);
+    Ok(frame_count)
+}
 
-        writer.flush()?;
+/// Adds the CM v4.0 DM extension blocks (L8/L9/L11/L254) to `dm_meta`, falling back to the
+/// defaults needed for the stream to validate as CM v4.0 when the config doesn't specify them.
+fn add_cm_v40_metadata(dm_meta: &mut VdrDmData, config: &GenerateConfig) {
+    if let Some(level8_list) = &config.level8 {
+        for l8 in level8_list {
+            dm_meta.add_level8_metadata(
+                l8.target_display_index,
+                l8.trim_slope,
+                l8.trim_offset,
+                l8.trim_power,
+                l8.trim_chroma_weight,
+                l8.trim_saturation_gain,
+                l8.ms_weight,
+                l8.target_mid_contrast,
+                l8.clip_trim,
+            );
+        }
+    }
 
-        Ok(())
+    if let Some(l9) = &config.level9 {
+        dm_meta.add_level9_metadata(l9.source_primary_index);
+    }
+
+    let level11 = config.level11.clone().unwrap_or_default();
+    dm_meta.add_level11_metadata(
+        level11.content_type,
+        level11.whitepoint,
+        level11.reference_mode_flag,
+    );
+
+    let level254 = config.level254.clone().unwrap_or_default();
+    dm_meta.add_level254_metadata(level254.dm_mode, level254.dm_version_index);
+}
+
+/// Looks up a `DistributionMaxRgb` bucket by its `Percentage` (the spec's buckets are
+/// `{1, 5, 10, 25, 50, 75, 90, 95, 99}`), returning the bucket's `Percentile` luminance in
+/// tenths of a nit.
+fn distribution_percentile(lum: &serde_json::Map<String, Value>, percentage: u64) -> Option<f64> {
+    lum.get("DistributionMaxRgb")?
+        .as_array()?
+        .iter()
+        .filter_map(|e| e.as_object())
+        .find(|e| e.get("Percentage").and_then(|v| v.as_u64()) == Some(percentage))
+        .and_then(|e| e.get("Percentile"))
+        .and_then(|v| v.as_u64())
+        .map(|v| v as f64)
+}
+
+/// Evaluates the HDR10+ Bezier tone-mapping curve at parameter `t` in `[0, 1]`, via De
+/// Casteljau's algorithm over `{(0, 0), (knee_x, knee_y), anchors.., (1, 1)}`. Returns both
+/// coordinates of the resulting curve point: the control points aren't evenly spaced in `x`, so
+/// `t` itself can't be used as a stand-in for normalized input luminance.
+fn evaluate_bezier_curve(knee_x: f64, knee_y: f64, anchors: &[f64], t: f64) -> (f64, f64) {
+    let anchor_count = anchors.len();
+
+    let mut points: Vec<(f64, f64)> = vec![(0.0, 0.0), (knee_x, knee_y)];
+    points.extend(anchors.iter().enumerate().map(|(i, anchor)| {
+        let anchor_x = knee_x + (1.0 - knee_x) * (i + 1) as f64 / (anchor_count + 1) as f64;
+        (anchor_x, *anchor)
+    }));
+    points.push((1.0, 1.0));
+
+    while points.len() > 1 {
+        points = points
+            .windows(2)
+            .map(|pair| {
+                let (x0, y0) = pair[0];
+                let (x1, y1) = pair[1];
+                (x0 + (x1 - x0) * t, y0 + (y1 - y0) * t)
+            })
+            .collect();
+    }
+
+    points[0]
+}
+
+/// Finds the curve's output `y` for a given normalized input luminance `target_x`, by
+/// binary-searching the parameter `t` until `X(t)` matches `target_x` (the curve's `X(t)` is
+/// monotonic since the control points are in ascending `x` order).
+fn bezier_y_at_x(knee_x: f64, knee_y: f64, anchors: &[f64], target_x: f64) -> f64 {
+    let (mut lo, mut hi) = (0.0, 1.0);
+
+    for _ in 0..32 {
+        let mid = (lo + hi) / 2.0;
+        let (x, _) = evaluate_bezier_curve(knee_x, knee_y, anchors, mid);
+
+        if x < target_x {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+
+    evaluate_bezier_curve(knee_x, knee_y, anchors, (lo + hi) / 2.0).1
+}
+
+/// Fits a slope/offset/power L2 trim to the HDR10+ Bezier curve at `target_nits`, so the
+/// generated trim tracks the source grade instead of flat defaults.
+fn bezier_to_level2_metadata(
+    target_nits: u16,
+    targeted_max_nits: f64,
+    knee_x: f64,
+    knee_y: f64,
+    anchors: &[f64],
+) -> Level2Metadata {
+    let x = (target_nits as f64 / targeted_max_nits.max(1.0)).clamp(0.0, 1.0);
+    let y_knee = bezier_y_at_x(knee_x, knee_y, anchors, knee_x);
+
+    // Gain just above the knee approximates the slope of the curve there.
+    let delta = 0.001;
+    let y_above_knee = bezier_y_at_x(knee_x, knee_y, anchors, (knee_x + delta).min(1.0));
+    let slope = ((y_above_knee - y_knee) / delta).clamp(0.0, 4.0);
+
+    // Curvature above the knee, expressed as a gamma fit between the knee and (1, 1).
+    let power = if x > knee_x {
+        let y = bezier_y_at_x(knee_x, knee_y, anchors, x);
+        let normalized_x =
+            ((x - knee_x) / (1.0 - knee_x).max(f64::EPSILON)).clamp(f64::EPSILON, 1.0);
+        let normalized_y =
+            ((y - y_knee) / (1.0 - y_knee).max(f64::EPSILON)).clamp(f64::EPSILON, 1.0);
+
+        (normalized_y.ln() / normalized_x.ln()).clamp(0.1, 4.0)
+    } else {
+        1.0
+    };
+
+    // `trim_offset`'s neutral value (2048, matching `default_trim()`) represents a zero shift
+    // from identity, not zero output — encode how far the curve has shifted *at the knee*
+    // relative to the identity line, not its absolute output.
+    let offset_from_identity = (y_knee - knee_x).clamp(-1.0, 1.0);
+
+    Level2Metadata {
+        target_nits,
+        trim_slope: (slope * 2048.0).round() as u16,
+        trim_offset: (offset_from_identity * 2048.0 + 2048.0)
+            .round()
+            .clamp(0.0, 4095.0) as u16,
+        trim_power: (power * 2048.0).round() as u16,
+        trim_chroma_weight: default_trim(),
+        trim_saturation_gain: default_trim(),
+        ms_weight: default_trim_neg(),
     }
 }
 
 fn parse_hdr10plus_for_l1(
     hdr10plus_path: &Option<PathBuf>,
-) -> (Option<Vec<Level1Metadata>>, Vec<usize>) {
+    target_nits: Option<u16>,
+) -> (
+    Option<Vec<Level1Metadata>>,
+    Option<Vec<Option<Level2Metadata>>>,
+    Vec<usize>,
+) {
     let mut l1_meta = None;
+    let mut l2_meta = None;
     let mut scene_cuts: Vec<usize> = Vec::new();
 
     if let Some(path) = hdr10plus_path {
@@ -291,7 +821,7 @@ fn parse_hdr10plus_for_l1(
         if let Some(json) = hdr10plus.as_object() {
             if let Some(scene_info) = json.get("SceneInfo") {
                 if let Some(list) = scene_info.as_array() {
-                    let info_list = list
+                    let frames: Vec<(Level1Metadata, Option<Level2Metadata>)> = list
                         .iter()
                         .filter_map(|e| e.as_object())
                         .map(|e| {
@@ -313,25 +843,86 @@ fn parse_hdr10plus_for_l1(
                                 scene_cuts.push(sequence_frame_index);
                             }
 
-                            Level1Metadata {
-                                min_pq: 0,
-                                max_pq: (nits_to_pq((max_rgb as f64 / 10.0).round() as u16)
-                                    * 4095.0)
-                                    .round() as u16,
-                                avg_pq: (nits_to_pq((avg_rgb as f64 / 10.0).round() as u16)
-                                    * 4095.0)
-                                    .round() as u16,
-                            }
+                            // The 99th percentile (the highest bucket HDR10+ defines) stands in
+                            // for the true peak, 50th for the average (falling back to
+                            // AverageRGB), and the lowest bucket present for the black level,
+                            // instead of hard-coding 0.
+                            let max_nits =
+                                distribution_percentile(lum, 99).unwrap_or(max_rgb as f64) / 10.0;
+                            let avg_nits =
+                                distribution_percentile(lum, 50).unwrap_or(avg_rgb as f64) / 10.0;
+                            let min_nits = lum
+                                .get("DistributionMaxRgb")
+                                .and_then(|v| v.as_array())
+                                .and_then(|list| {
+                                    list.iter()
+                                        .filter_map(|e| e.as_object())
+                                        .filter_map(|e| e.get("Percentage")?.as_u64())
+                                        .min()
+                                        .and_then(|pct| distribution_percentile(lum, pct))
+                                })
+                                .unwrap_or(0.0)
+                                / 10.0;
+
+                            let l1 = Level1Metadata {
+                                min_pq: (nits_to_pq(min_nits.round() as u16) * 4095.0).round()
+                                    as u16,
+                                max_pq: (nits_to_pq(max_nits.round() as u16) * 4095.0).round()
+                                    as u16,
+                                avg_pq: (nits_to_pq(avg_nits.round() as u16) * 4095.0).round()
+                                    as u16,
+                            };
+
+                            let l2 = target_nits.and_then(|target| {
+                                let targeted_max_nits = e
+                                    .get("TargetedSystemDisplayMaximumLuminance")
+                                    .and_then(|v| v.as_f64())?;
+
+                                let bezier = e.get("BezierCurveData")?.as_object()?;
+                                let knee_x = bezier.get("KneePointX")?.as_f64()?;
+                                let knee_y = bezier.get("KneePointY")?.as_f64()?;
+                                let anchors: Vec<f64> = bezier
+                                    .get("Anchors")
+                                    .and_then(|v| v.as_array())
+                                    .map(|a| a.iter().filter_map(|v| v.as_f64()).collect())
+                                    .unwrap_or_default();
+
+                                Some(bezier_to_level2_metadata(
+                                    target,
+                                    targeted_max_nits,
+                                    knee_x,
+                                    knee_y,
+                                    &anchors,
+                                ))
+                            });
+
+                            (l1, l2)
                         })
                         .collect();
 
-                    l1_meta = Some(info_list)
+                    l2_meta = Some(frames.iter().map(|(_, l2)| l2.clone()).collect());
+                    l1_meta = Some(frames.into_iter().map(|(l1, _)| l1).collect());
                 }
             }
         }
     }
 
-    (l1_meta, scene_cuts)
+    (l1_meta, l2_meta, scene_cuts)
+}
+
+/// The SMPTE ST 2084 PQ OETF, applied directly to `nits` rather than going through `nits_to_pq`
+/// (which only accepts whole nits and so can't represent a sub-nit mastering display black
+/// level).
+fn nits_to_pq_precise(nits: f64) -> f64 {
+    const M1: f64 = 2610.0 / 16384.0;
+    const M2: f64 = 2523.0 / 4096.0 * 128.0;
+    const C1: f64 = 3424.0 / 4096.0;
+    const C2: f64 = 2413.0 / 4096.0 * 32.0;
+    const C3: f64 = 2392.0 / 4096.0 * 32.0;
+
+    let y = (nits / 10_000.0).max(0.0).powf(M1);
+
+    ((C1 + C2 * y) / (1.0 + C3 * y)).powf(M2)
 }
 
 fn default_trim() -> u16 {
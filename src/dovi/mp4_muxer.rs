@@ -0,0 +1,284 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::DoviRpu;
+
+/// Writes a box's 4-byte type followed by its children, then back-patches the 32-bit size
+/// prefix once the children are known. Mirrors the length-prefixed box writing the mp4/fmp4
+/// muxers already use.
+fn write_box(buf: &mut Vec<u8>, box_type: &[u8; 4], content: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+    buf.extend_from_slice(box_type);
+
+    content(buf);
+
+    let size = (buf.len() - start) as u32;
+    buf[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Same as `write_box`, but for a "full box" that also carries a version + flags header.
+fn write_full_box(
+    buf: &mut Vec<u8>,
+    box_type: &[u8; 4],
+    version: u8,
+    flags: u32,
+    content: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(buf, box_type, |buf| {
+        buf.push(version);
+        buf.extend_from_slice(&flags.to_be_bytes()[1..]);
+        content(buf);
+    });
+}
+
+/// The identity transformation matrix every `tkhd`/`mvhd` carries.
+fn write_unity_matrix(buf: &mut Vec<u8>) {
+    const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+    for v in MATRIX {
+        buf.extend_from_slice(&v.to_be_bytes());
+    }
+}
+
+/// The Dolby Vision decoder configuration record carried in the `dvcC`/`dvvC` box, computed
+/// directly from the fields the generator already sets on a profile 8 `DoviRpu`.
+#[derive(Debug, Clone, Copy)]
+pub struct DoviDecoderConfigurationRecord {
+    pub dv_version_major: u8,
+    pub dv_version_minor: u8,
+    pub dv_profile: u8,
+    pub dv_level: u8,
+    pub rpu_present_flag: bool,
+    pub el_present_flag: bool,
+    pub bl_present_flag: bool,
+    pub dv_bl_signal_compatibility_id: u8,
+}
+
+impl DoviDecoderConfigurationRecord {
+    /// Profile 8 carries the RPU alongside a single base layer, with no enhancement layer.
+    pub fn from_rpu(rpu: &DoviRpu, dv_level: u8) -> Self {
+        Self {
+            dv_version_major: 1,
+            dv_version_minor: 0,
+            dv_profile: rpu.dovi_profile,
+            dv_level,
+            rpu_present_flag: true,
+            el_present_flag: false,
+            bl_present_flag: true,
+            dv_bl_signal_compatibility_id: 1,
+        }
+    }
+
+    /// The box type is `dvvC` when the record signals an enhancement layer, `dvcC` otherwise.
+    /// Profile 8 generation only ever emits `dvcC`.
+    fn box_type(&self) -> &'static [u8; 4] {
+        if self.el_present_flag {
+            b"dvvC"
+        } else {
+            b"dvcC"
+        }
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_box(buf, self.box_type(), |buf| {
+            buf.push(self.dv_version_major);
+            buf.push(self.dv_version_minor);
+            buf.push((self.dv_profile << 1) | (self.dv_level >> 5));
+            buf.push(
+                (self.dv_level << 3)
+                    | ((self.rpu_present_flag as u8) << 2)
+                    | ((self.el_present_flag as u8) << 1)
+                    | (self.bl_present_flag as u8),
+            );
+            buf.push(self.dv_bl_signal_compatibility_id << 4);
+            buf.extend_from_slice(&[0; 3]); // remaining 28 reserved bits
+            buf.extend_from_slice(&[0; 16]); // reserved
+        });
+    }
+}
+
+/// Writes the generated RPUs (already-encoded NAL payloads, without start codes) packaged in a
+/// fragmented MP4: a `moov` describing one video track configured with the Dolby Vision
+/// decoder configuration record, followed by one `moof`/`mdat` fragment per sample.
+pub fn write_fmp4(
+    rpus: &[Vec<u8>],
+    config_record: &DoviDecoderConfigurationRecord,
+    out_path: &Path,
+) -> Result<(), std::io::Error> {
+    let mut writer = BufWriter::new(File::create(out_path)?);
+
+    // Arbitrary but valid (non-zero) timescale; the actual duration is signaled per-fragment.
+    let timescale: u32 = 1000;
+
+    let mut buf = Vec::new();
+
+    write_box(&mut buf, b"ftyp", |buf| {
+        buf.extend_from_slice(b"iso5");
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        buf.extend_from_slice(b"iso5dash");
+    });
+
+    write_box(&mut buf, b"moov", |buf| {
+        write_full_box(buf, b"mvhd", 0, 0, |buf| {
+            buf.extend_from_slice(&[0; 8]); // creation/modification time
+            buf.extend_from_slice(&timescale.to_be_bytes());
+            buf.extend_from_slice(&0u32.to_be_bytes()); // duration: unknown, signaled per-fragment
+            buf.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate 1.0
+            buf.extend_from_slice(&0x0100u16.to_be_bytes()); // volume 1.0
+            buf.extend_from_slice(&[0; 2]); // reserved
+            buf.extend_from_slice(&[0; 8]); // reserved
+            write_unity_matrix(buf);
+            buf.extend_from_slice(&[0; 24]); // pre_defined
+            buf.extend_from_slice(&2u32.to_be_bytes()); // next_track_ID
+        });
+
+        write_box(buf, b"trak", |buf| {
+            write_full_box(buf, b"tkhd", 0, 3, |buf| {
+                buf.extend_from_slice(&[0; 8]); // creation/modification time
+                buf.extend_from_slice(&1u32.to_be_bytes()); // track_ID
+                buf.extend_from_slice(&[0; 4]); // reserved
+                buf.extend_from_slice(&0u32.to_be_bytes()); // duration
+                buf.extend_from_slice(&[0; 8]); // reserved
+                buf.extend_from_slice(&[0; 2]); // layer
+                buf.extend_from_slice(&[0; 2]); // alternate_group
+                buf.extend_from_slice(&[0; 2]); // volume: 0, video track
+                buf.extend_from_slice(&[0; 2]); // reserved
+                write_unity_matrix(buf);
+                buf.extend_from_slice(&[0; 4]); // width: unknown
+                buf.extend_from_slice(&[0; 4]); // height: unknown
+            });
+
+            write_box(buf, b"mdia", |buf| {
+                write_full_box(buf, b"mdhd", 0, 0, |buf| {
+                    buf.extend_from_slice(&[0; 8]); // creation/modification time
+                    buf.extend_from_slice(&timescale.to_be_bytes());
+                    buf.extend_from_slice(&0u32.to_be_bytes()); // duration
+                    buf.extend_from_slice(&[0x55, 0xc4]); // language: "und"
+                    buf.extend_from_slice(&[0; 2]); // pre_defined
+                });
+
+                write_full_box(buf, b"hdlr", 0, 0, |buf| {
+                    buf.extend_from_slice(&[0; 4]); // pre_defined
+                    buf.extend_from_slice(b"vide"); // handler_type
+                    buf.extend_from_slice(&[0; 12]); // reserved
+                    buf.push(0); // name: empty, null-terminated
+                });
+
+                write_box(buf, b"minf", |buf| {
+                    write_full_box(buf, b"vmhd", 0, 1, |buf| {
+                        buf.extend_from_slice(&[0; 2]); // graphicsmode
+                        buf.extend_from_slice(&[0; 6]); // opcolor
+                    });
+
+                    write_box(buf, b"dinf", |buf| {
+                        write_full_box(buf, b"dref", 0, 0, |buf| {
+                            buf.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+                                                                        // Self-contained (flags = 1): no data beyond the full-box header.
+                            write_full_box(buf, b"url ", 0, 1, |_buf| {});
+                        });
+                    });
+
+                    write_box(buf, b"stbl", |buf| {
+                        write_full_box(buf, b"stsd", 0, 0, |buf| {
+                            buf.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+
+                            write_box(buf, b"hvc1", |buf| {
+                                buf.extend_from_slice(&[0; 6]); // reserved
+                                buf.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+                                buf.extend_from_slice(&[0; 70]); // VisualSampleEntry fixed fields
+
+                                config_record.write(buf);
+                            });
+                        });
+
+                        // Empty: samples are described per-fragment in moof/traf, as is
+                        // standard for a fragmented MP4 initialization segment.
+                        write_full_box(buf, b"stts", 0, 0, |buf| {
+                            buf.extend_from_slice(&0u32.to_be_bytes()); // entry_count
+                        });
+                        write_full_box(buf, b"stsc", 0, 0, |buf| {
+                            buf.extend_from_slice(&0u32.to_be_bytes()); // entry_count
+                        });
+                        write_full_box(buf, b"stsz", 0, 0, |buf| {
+                            buf.extend_from_slice(&0u32.to_be_bytes()); // sample_size
+                            buf.extend_from_slice(&0u32.to_be_bytes()); // sample_count
+                        });
+                        write_full_box(buf, b"stco", 0, 0, |buf| {
+                            buf.extend_from_slice(&0u32.to_be_bytes()); // entry_count
+                        });
+                    });
+                });
+            });
+        });
+
+        write_box(buf, b"mvex", |buf| {
+            write_full_box(buf, b"trex", 0, 0, |buf| {
+                buf.extend_from_slice(&1u32.to_be_bytes()); // track_id
+                buf.extend_from_slice(&1u32.to_be_bytes()); // default_sample_description_index
+                buf.extend_from_slice(&1u32.to_be_bytes()); // default_sample_duration
+                buf.extend_from_slice(&0u32.to_be_bytes()); // default_sample_size
+                buf.extend_from_slice(&0u32.to_be_bytes()); // default_sample_flags
+            });
+        });
+    });
+
+    writer.write_all(&buf)?;
+
+    for (i, rpu) in rpus.iter().enumerate() {
+        write_fragment(&mut writer, i as u32 + 1, rpu)?;
+    }
+
+    writer.flush()
+}
+
+/// One `moof`/`mdat` pair carrying a single RPU-bearing sample, sequence-numbered `fragment_no`.
+fn write_fragment(
+    writer: &mut BufWriter<File>,
+    fragment_no: u32,
+    rpu: &[u8],
+) -> Result<(), std::io::Error> {
+    let mut buf = Vec::new();
+    let mut data_offset_pos = 0;
+
+    write_box(&mut buf, b"moof", |buf| {
+        write_full_box(buf, b"mfhd", 0, 0, |buf| {
+            buf.extend_from_slice(&fragment_no.to_be_bytes());
+        });
+
+        write_box(buf, b"traf", |buf| {
+            // default-base-is-moof (0x020000) | default-sample-duration-present (0x000008)
+            write_full_box(buf, b"tfhd", 0, 0x02_0008, |buf| {
+                buf.extend_from_slice(&1u32.to_be_bytes()); // track_id
+                buf.extend_from_slice(&1u32.to_be_bytes()); // default_sample_duration
+            });
+
+            write_full_box(buf, b"tfdt", 0, 0, |buf| {
+                buf.extend_from_slice(&(fragment_no - 1).to_be_bytes());
+            });
+
+            // data-offset-present (0x000001) | sample-size-present (0x000200)
+            write_full_box(buf, b"trun", 0, 0x201, |buf| {
+                buf.extend_from_slice(&1u32.to_be_bytes()); // sample_count
+
+                // Patched below once the moof's final size (and so the offset of `mdat`'s
+                // payload, relative to this moof's start) is known.
+                data_offset_pos = buf.len();
+                buf.extend_from_slice(&0i32.to_be_bytes()); // data_offset
+
+                buf.extend_from_slice(&(rpu.len() as u32).to_be_bytes()); // sample_size
+            });
+        });
+    });
+
+    // `tfhd`'s default-base-is-moof makes `data_offset` relative to this moof's start; the
+    // sample data itself starts past the moof and the 8-byte `mdat` box header.
+    let data_offset = buf.len() as i32 + 8;
+    buf[data_offset_pos..data_offset_pos + 4].copy_from_slice(&data_offset.to_be_bytes());
+
+    write_box(&mut buf, b"mdat", |buf| buf.extend_from_slice(rpu));
+
+    writer.write_all(&buf)
+}
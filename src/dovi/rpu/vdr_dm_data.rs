@@ -0,0 +1,244 @@
+use crate::dovi::generator::{
+    CmVersion, GenerateConfig, Level11Metadata, Level254Metadata, Level2Metadata, Level5Metadata,
+    Level6Metadata, Level8Metadata, Level9Metadata,
+};
+
+/// One frame's worth of DM (Display Management) metadata: the VDR extension blocks that sit
+/// alongside the VDR RPU data in a profile 8 RPU. `DoviRpu::write_rpu_data` serializes these via
+/// `write_ext_blocks`, one block per populated level (one per entry for L2/L8), in ascending
+/// level order.
+#[derive(Default, Debug, Clone)]
+pub struct VdrDmData {
+    pub scene_cut: bool,
+
+    pub source_min_pq: Option<u16>,
+    pub source_max_pq: Option<u16>,
+
+    cm_version: CmVersion,
+
+    level1: Option<(u16, u16, u16)>,
+    level2: Vec<Level2Metadata>,
+    level3: Option<(u16, u16, u16)>,
+    level5: Option<Level5Metadata>,
+    level6: Option<Level6Metadata>,
+
+    level8: Vec<Level8Metadata>,
+    level9: Option<Level9Metadata>,
+    level11: Option<Level11Metadata>,
+    level254: Option<Level254Metadata>,
+}
+
+impl VdrDmData {
+    /// Seeds the uniform per-run fields (`source_min_pq`/`source_max_pq`, `cm_version`,
+    /// `level2`/`level5`/`level6`) from a `GenerateConfig`. Per-frame levels (L1/L3) and the CM
+    /// v4.0 blocks (L8/L9/L11/L254) are added afterwards via the `add_level*` methods.
+    pub fn from_config(config: &GenerateConfig) -> Self {
+        Self {
+            source_min_pq: config.source_min_pq,
+            source_max_pq: config.source_max_pq,
+            cm_version: config.cm_version,
+            level2: config.level2.clone().unwrap_or_default(),
+            level5: config.level5.clone(),
+            level6: config.level6.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn set_scene_cut(&mut self, scene_cut: bool) {
+        self.scene_cut = scene_cut;
+    }
+
+    pub fn add_level1_metadata(&mut self, min_pq: u16, max_pq: u16, avg_pq: u16) {
+        self.level1 = Some((min_pq, max_pq, avg_pq));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_level2_metadata(
+        &mut self,
+        target_nits: u16,
+        trim_slope: u16,
+        trim_offset: u16,
+        trim_power: u16,
+        trim_chroma_weight: u16,
+        trim_saturation_gain: u16,
+        ms_weight: i16,
+    ) {
+        self.level2.push(Level2Metadata {
+            target_nits,
+            target_display: None,
+            trim_slope,
+            trim_offset,
+            trim_power,
+            trim_chroma_weight,
+            trim_saturation_gain,
+            ms_weight,
+        });
+    }
+
+    pub fn add_level3_metadata(
+        &mut self,
+        min_pq_offset: u16,
+        max_pq_offset: u16,
+        avg_pq_offset: u16,
+    ) {
+        self.level3 = Some((min_pq_offset, max_pq_offset, avg_pq_offset));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_level8_metadata(
+        &mut self,
+        target_display_index: u8,
+        trim_slope: u16,
+        trim_offset: u16,
+        trim_power: u16,
+        trim_chroma_weight: u16,
+        trim_saturation_gain: u16,
+        ms_weight: i16,
+        target_mid_contrast: u16,
+        clip_trim: u16,
+    ) {
+        self.level8.push(Level8Metadata {
+            target_display_index,
+            target_display: None,
+            trim_slope,
+            trim_offset,
+            trim_power,
+            trim_chroma_weight,
+            trim_saturation_gain,
+            ms_weight,
+            target_mid_contrast,
+            clip_trim,
+        });
+    }
+
+    pub fn add_level9_metadata(&mut self, source_primary_index: u8) {
+        self.level9 = Some(Level9Metadata {
+            source_primary_index,
+        });
+    }
+
+    pub fn add_level11_metadata(
+        &mut self,
+        content_type: u8,
+        whitepoint: u8,
+        reference_mode_flag: bool,
+    ) {
+        self.level11 = Some(Level11Metadata {
+            content_type,
+            whitepoint,
+            reference_mode_flag,
+        });
+    }
+
+    pub fn add_level254_metadata(&mut self, dm_mode: u8, dm_version_index: u8) {
+        self.level254 = Some(Level254Metadata {
+            dm_mode,
+            dm_version_index,
+        });
+    }
+
+    /// Serializes the populated levels as `ext_dm_data_block`s: `ext_block_level` (u8),
+    /// `ext_block_length` and the level's payload. The core blocks (L1/L2/L3/L5/L6) always use
+    /// the 1-byte `ext_block_length` the v2.9 layout defines; only the CM v4.0 blocks
+    /// (L8/L9/L11/L254) switch to the wider 32-bit `ext_block_length` that layout calls for.
+    pub fn write_ext_blocks(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        if let Some((min_pq, max_pq, avg_pq)) = self.level1 {
+            let mut payload = Vec::with_capacity(6);
+            payload.extend_from_slice(&min_pq.to_be_bytes());
+            payload.extend_from_slice(&max_pq.to_be_bytes());
+            payload.extend_from_slice(&avg_pq.to_be_bytes());
+            write_ext_block(&mut out, 1, &payload, false);
+        }
+
+        for l2 in &self.level2 {
+            let mut payload = Vec::with_capacity(12);
+            payload.extend_from_slice(&l2.target_nits.to_be_bytes());
+            payload.extend_from_slice(&l2.trim_slope.to_be_bytes());
+            payload.extend_from_slice(&l2.trim_offset.to_be_bytes());
+            payload.extend_from_slice(&l2.trim_power.to_be_bytes());
+            payload.extend_from_slice(&l2.trim_chroma_weight.to_be_bytes());
+            payload.extend_from_slice(&l2.trim_saturation_gain.to_be_bytes());
+            payload.extend_from_slice(&l2.ms_weight.to_be_bytes());
+            write_ext_block(&mut out, 2, &payload, false);
+        }
+
+        if let Some((min_pq_offset, max_pq_offset, avg_pq_offset)) = self.level3 {
+            let mut payload = Vec::with_capacity(6);
+            payload.extend_from_slice(&min_pq_offset.to_be_bytes());
+            payload.extend_from_slice(&max_pq_offset.to_be_bytes());
+            payload.extend_from_slice(&avg_pq_offset.to_be_bytes());
+            write_ext_block(&mut out, 3, &payload, false);
+        }
+
+        if let Some(l5) = &self.level5 {
+            let mut payload = Vec::with_capacity(8);
+            payload.extend_from_slice(&l5.active_area_left_offset.to_be_bytes());
+            payload.extend_from_slice(&l5.active_area_right_offset.to_be_bytes());
+            payload.extend_from_slice(&l5.active_area_top_offset.to_be_bytes());
+            payload.extend_from_slice(&l5.active_area_bottom_offset.to_be_bytes());
+            write_ext_block(&mut out, 5, &payload, false);
+        }
+
+        if let Some(l6) = &self.level6 {
+            let mut payload = Vec::with_capacity(8);
+            payload.extend_from_slice(&l6.max_display_mastering_luminance.to_be_bytes());
+            payload.extend_from_slice(&l6.min_display_mastering_luminance.to_be_bytes());
+            payload.extend_from_slice(&l6.max_content_light_level.to_be_bytes());
+            payload.extend_from_slice(&l6.max_frame_average_light_level.to_be_bytes());
+            write_ext_block(&mut out, 6, &payload, false);
+        }
+
+        if self.cm_version == CmVersion::CmV40 {
+            for l8 in &self.level8 {
+                let mut payload = Vec::with_capacity(13);
+                payload.push(l8.target_display_index);
+                payload.extend_from_slice(&l8.trim_slope.to_be_bytes());
+                payload.extend_from_slice(&l8.trim_offset.to_be_bytes());
+                payload.extend_from_slice(&l8.trim_power.to_be_bytes());
+                payload.extend_from_slice(&l8.trim_chroma_weight.to_be_bytes());
+                payload.extend_from_slice(&l8.trim_saturation_gain.to_be_bytes());
+                payload.extend_from_slice(&l8.ms_weight.to_be_bytes());
+                payload.extend_from_slice(&l8.target_mid_contrast.to_be_bytes());
+                payload.extend_from_slice(&l8.clip_trim.to_be_bytes());
+                write_ext_block(&mut out, 8, &payload, true);
+            }
+
+            if let Some(l9) = &self.level9 {
+                write_ext_block(&mut out, 9, &[l9.source_primary_index], true);
+            }
+
+            if let Some(l11) = &self.level11 {
+                let payload = [
+                    l11.content_type,
+                    l11.whitepoint,
+                    l11.reference_mode_flag as u8,
+                ];
+                write_ext_block(&mut out, 11, &payload, true);
+            }
+
+            if let Some(l254) = &self.level254 {
+                let payload = [l254.dm_mode, l254.dm_version_index];
+                write_ext_block(&mut out, 254, &payload, true);
+            }
+        }
+
+        out
+    }
+}
+
+/// Writes one `ext_dm_data_block`: `ext_block_level` (u8), `ext_block_length` (u8, or u32 when
+/// `wide` is set), then `payload`. Only the CM v4.0 blocks (L8/L9/L11/L254) are `wide`; the core
+/// blocks keep the 1-byte length regardless of `cm_version`.
+fn write_ext_block(out: &mut Vec<u8>, ext_block_level: u8, payload: &[u8], wide: bool) {
+    out.push(ext_block_level);
+
+    if wide {
+        out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    } else {
+        out.push(payload.len() as u8);
+    }
+
+    out.extend_from_slice(payload);
+}